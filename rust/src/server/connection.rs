@@ -0,0 +1,84 @@
+//! Per-connection state tracked by the server for each connected client slot.
+
+use std::net::SocketAddr;
+
+use common::*;
+use server::ClientId;
+
+/// Size of the replay protection sliding window, keyed by `sequence % REPLAY_PROTECTION_BUFFER_SIZE`.
+const REPLAY_PROTECTION_BUFFER_SIZE: usize = 256;
+
+/// Guards a connection against replayed or duplicated packet sequence numbers.
+///
+/// Mirrors the replay-protection subsystem carried alongside the packet layer: a
+/// `most_recent_sequence` high-water mark plus a fixed-size ring of the last-seen
+/// sequence per slot, so out-of-order delivery within the window is still accepted
+/// while old or repeated sequences are dropped.
+pub struct ReplayProtection {
+    most_recent_sequence: u64,
+    received_packet: [u64; REPLAY_PROTECTION_BUFFER_SIZE],
+}
+
+impl ReplayProtection {
+    pub fn new() -> ReplayProtection {
+        ReplayProtection {
+            most_recent_sequence: 0,
+            received_packet: [u64::MAX; REPLAY_PROTECTION_BUFFER_SIZE],
+        }
+    }
+
+    /// Returns `true` if `sequence` is too old or has already been seen and should be
+    /// dropped. Otherwise records it as seen and returns `false`.
+    pub fn packet_already_received(&mut self, sequence: u64) -> bool {
+        if sequence + REPLAY_PROTECTION_BUFFER_SIZE as u64 < self.most_recent_sequence {
+            return true;
+        }
+
+        let idx = (sequence % REPLAY_PROTECTION_BUFFER_SIZE as u64) as usize;
+        if self.received_packet[idx] == sequence {
+            return true;
+        }
+
+        self.received_packet[idx] = sequence;
+        self.most_recent_sequence = u64::max(self.most_recent_sequence, sequence);
+
+        false
+    }
+}
+
+#[derive(Clone)]
+pub struct RetryState {
+    pub last_update: f64,
+    /// Absolute server time (see `Server::time`) of the next scheduled retry/keep-alive
+    /// send. Fires immediately the first time this state is ticked.
+    pub next_retry: f64,
+    pub retry_count: u32,
+}
+
+impl RetryState {
+    pub fn new(time: f64) -> RetryState {
+        RetryState {
+            last_update: time,
+            next_retry: time,
+            retry_count: 0,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum ConnectionState {
+    PendingResponse(RetryState),
+    Idle(RetryState),
+    Connected,
+    TimedOut,
+    Disconnected
+}
+
+pub struct Connection {
+    pub client_id: ClientId,
+    pub state: ConnectionState,
+    pub server_to_client_key: [u8; NETCODE_KEY_BYTES],
+    pub client_to_server_key: [u8; NETCODE_KEY_BYTES],
+    pub addr: SocketAddr,
+    pub replay_protection: ReplayProtection,
+}
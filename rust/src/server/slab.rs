@@ -0,0 +1,92 @@
+//! Stable integer-keyed arena for connection storage, so a client's slot index stays
+//! valid (and O(1) to look up) across inserts and removals of other clients.
+
+use std::mem;
+
+enum Entry<T> {
+    Occupied(T),
+    Vacant(usize)
+}
+
+pub struct Slab<T> {
+    entries: Vec<Entry<T>>,
+    next: usize,
+    len: usize
+}
+
+impl<T> Slab<T> {
+    pub fn with_capacity(capacity: usize) -> Slab<T> {
+        Slab {
+            entries: Vec::with_capacity(capacity),
+            next: 0,
+            len: 0
+        }
+    }
+
+    /// Number of occupied entries.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether there are any occupied entries.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of slots ever allocated, including vacated ones. Upper bound for
+    /// iterating every valid key.
+    pub fn slots(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn insert(&mut self, val: T) -> usize {
+        let key = self.next;
+        self.len += 1;
+
+        if key == self.entries.len() {
+            self.entries.push(Entry::Occupied(val));
+            self.next = key + 1;
+        } else {
+            self.next = match self.entries[key] {
+                Entry::Vacant(next) => next,
+                Entry::Occupied(_) => unreachable!("slab free list pointed at an occupied entry")
+            };
+            self.entries[key] = Entry::Occupied(val);
+        }
+
+        key
+    }
+
+    pub fn remove(&mut self, key: usize) -> Option<T> {
+        if key >= self.entries.len() {
+            return None
+        }
+
+        match mem::replace(&mut self.entries[key], Entry::Vacant(self.next)) {
+            Entry::Occupied(val) => {
+                self.next = key;
+                self.len -= 1;
+                Some(val)
+            },
+            vacant => {
+                //Wasn't occupied, put it back untouched.
+                self.entries[key] = vacant;
+                None
+            }
+        }
+    }
+
+    pub fn get(&self, key: usize) -> Option<&T> {
+        match self.entries.get(key) {
+            Some(&Entry::Occupied(ref val)) => Some(val),
+            _ => None
+        }
+    }
+
+    pub fn get_mut(&mut self, key: usize) -> Option<&mut T> {
+        match self.entries.get_mut(key) {
+            Some(&mut Entry::Occupied(ref mut val)) => Some(val),
+            _ => None
+        }
+    }
+}
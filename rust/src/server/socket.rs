@@ -0,0 +1,39 @@
+//! Thin abstraction over the transport socket so the server can be tested without real UDP.
+
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
+
+/// Provides the socket operations the server needs, generic so tests can substitute
+/// an in-memory transport instead of a real `UdpSocket`.
+pub trait SocketProvider<I> {
+    fn bind(addr: &SocketAddr) -> io::Result<I>;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+    fn send_to(&self, buf: &[u8], addr: &SocketAddr) -> io::Result<usize>;
+    /// Sets how long `recv_from` will block waiting for a datagram. `None` blocks
+    /// indefinitely.
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()>;
+}
+
+impl SocketProvider<UdpSocket> for UdpSocket {
+    fn bind(addr: &SocketAddr) -> io::Result<UdpSocket> {
+        UdpSocket::bind(addr)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf)
+    }
+
+    fn send_to(&self, buf: &[u8], addr: &SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, addr)
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        UdpSocket::set_read_timeout(self, dur)
+    }
+}